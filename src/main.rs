@@ -3,6 +3,154 @@ use std::io;
 use nostr_types::{PublicKey, RelayInformationDocument, RelayUrl, Unixtime};
 use serde::{Deserialize, Serialize};
 
+/// Runtime knobs, parsed from the command line.
+pub struct Config {
+    /// Skip relays whose `limitation.min_pow_difficulty` exceeds this.
+    pub max_min_pow: u32,
+    /// Keep (but penalize) relays that require AUTH instead of dropping them.
+    pub allow_auth: bool,
+    /// Hostname patterns to avoid, gathered from `--ban` and `--bans-file`.
+    pub bans: Bans,
+    /// z-value for the Wilson score interval (1.96 = 95% confidence). Larger
+    /// values are more conservative about relays with few attempts.
+    pub z: f32,
+    /// How the ranked list is printed.
+    pub format: OutputFormat,
+    /// How many relays to emit.
+    pub count: usize,
+    /// Maximum relays sharing one operator pubkey in the output (0 = no cap).
+    pub max_per_operator: usize,
+    /// Maximum relays sharing one apex domain in the output (0 = no cap).
+    pub max_per_domain: usize,
+}
+
+/// Output representation selected with `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable debug dump, one relay per line (the default).
+    Text,
+    /// A JSON array of the ranked relays with their scores and operator keys.
+    Json,
+    /// An unsigned kind:10002 NIP-65 relay-list event, ready to sign and publish.
+    Nip65,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_min_pow: 0,
+            allow_auth: false,
+            bans: Bans::default(),
+            z: 1.96,
+            format: OutputFormat::Text,
+            count: 20,
+            max_per_operator: 2,
+            max_per_domain: 2,
+        }
+    }
+}
+
+impl Config {
+    fn from_args() -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config = Config::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-min-pow" => {
+                    if let Some(v) = args.next() {
+                        config.max_min_pow = v.parse().unwrap_or(0);
+                    }
+                }
+                "--allow-auth" => config.allow_auth = true,
+                "--z" => {
+                    if let Some(v) = args.next() {
+                        config.z = v.parse().unwrap_or(1.96);
+                    }
+                }
+                "--format" => {
+                    if let Some(v) = args.next() {
+                        config.format = match v.as_str() {
+                            "json" => OutputFormat::Json,
+                            "nip65" => OutputFormat::Nip65,
+                            _ => OutputFormat::Text,
+                        };
+                    }
+                }
+                "--count" => {
+                    if let Some(v) = args.next() {
+                        config.count = v.parse().unwrap_or(20);
+                    }
+                }
+                "--max-per-operator" => {
+                    if let Some(v) = args.next() {
+                        config.max_per_operator = v.parse().unwrap_or(2);
+                    }
+                }
+                "--max-per-domain" => {
+                    if let Some(v) = args.next() {
+                        config.max_per_domain = v.parse().unwrap_or(2);
+                    }
+                }
+                "--ban" => {
+                    if let Some(v) = args.next() {
+                        config.bans.push(v);
+                    }
+                }
+                "--bans-file" => {
+                    if let Some(path) = args.next() {
+                        config.bans.load_file(&path)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A curated list of relay hostname patterns to keep out of the ranking.
+///
+/// A pattern matches a relay when it appears anywhere in the relay URL, so a
+/// bare hostname like `spam.example` bans that host while a broader fragment
+/// like `.onion` bans a whole family. Blank lines and `#` comments in a bans
+/// file are ignored.
+#[derive(Debug, Clone)]
+pub struct Bans {
+    patterns: Vec<String>,
+}
+
+impl Default for Bans {
+    fn default() -> Bans {
+        // My personal relay has high stats because I use it for archival, so
+        // it is banned by default to keep it out of the public ranking.
+        Bans {
+            patterns: vec!["mikedilger".to_owned()],
+        }
+    }
+}
+
+impl Bans {
+    fn push(&mut self, pattern: String) {
+        self.patterns.push(pattern);
+    }
+
+    fn load_file(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.patterns.push(line.to_owned());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        self.patterns.iter().any(|p| url.contains(p.as_str()))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Relay {
     pub url: RelayUrl,
@@ -17,17 +165,39 @@ pub struct Relay {
     pub last_attempt_nip11: Option<u64>,
 }
 
-#[derive(Debug, Clone)]
+impl Relay {
+    /// Whether this relay should be kept out of the ranking entirely, mirroring
+    /// gossip's own `should_avoid()`: a rank of 0 or a `hidden` flag is an
+    /// explicit "never use me", and a hostname match against the curated
+    /// `bans` list lets a maintainer drop known-bad relays without recompiling.
+    pub fn should_avoid(&self, bans: &Bans) -> bool {
+        if self.rank == 0 {
+            return true;
+        }
+        if self.hidden {
+            return true;
+        }
+        bans.matches(self.url.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Scoring {
     pub score: f32,
     pub ago: i64,
     pub attempts: u64,
     pub success: u64,
     pub rate: f32,
+    /// Seconds since the relay last sent a general EOSE, or `None` if it never
+    /// did. A large or absent value means the relay is effectively dead for
+    /// reading even if connections still succeed.
+    pub eose_ago: Option<i64>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
+    let config = Config::from_args()?;
+
     let mut ranked: Vec<(Relay, Scoring, PublicKey)> = Vec::new();
 
     let lines = io::stdin().lines();
@@ -35,6 +205,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let line = line?;
         let relay: Relay = serde_json::from_str(&line)?;
 
+        // Skip relays we've been told to avoid (rank 0, hidden, or banned)
+        if relay.should_avoid(&config.bans) {
+            continue;
+        }
+
         // Skip if we never successfully connected to it
         if relay.success_count==0 {
             continue;
@@ -65,27 +240,116 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             continue;
         }
 
-        // Skip my perosnal relay (it has high stats because I use it for archival)
-        if relay.url.as_str().contains("mikedilger") {
-            continue;
+        // Skip based on the structured NIP-11 `limitation` block: a relay that
+        // requires payment or restricts writes can't serve as an open-write
+        // relay, and one demanding more proof-of-work than we tolerate is out
+        // of reach for an ordinary user. AUTH-required relays are dropped too
+        // unless `--allow-auth` is set, in which case they are kept and
+        // penalized in `rank()` instead.
+        if let Some(limitation) = &nip11.limitation {
+            if limitation.payment_required == Some(true) {
+                continue;
+            }
+            if limitation.restricted_writes == Some(true) {
+                continue;
+            }
+            if let Some(min_pow) = limitation.min_pow_difficulty {
+                if min_pow > config.max_min_pow {
+                    continue;
+                }
+            }
+            if !config.allow_auth && limitation.auth_required == Some(true) {
+                continue;
+            }
         }
 
         // Score
-        let scoring = rank(&relay);
+        let scoring = rank(&relay, config.z);
 
         ranked.push((relay, scoring, pubkey));
     }
 
     ranked.sort_by(|a,b| b.1.score.partial_cmp(&a.1.score).unwrap());
 
-    for (relay, scoring, _pubkey) in ranked.iter().take(20) {
-        println!("{} {:?}", relay.url, scoring);
+    // Diversity pass: having sorted best-first, drop relays once an operator
+    // pubkey or apex domain has already placed its quota, so a single operator
+    // running many good relays can't monopolize the recommendation list.
+    let mut per_operator: std::collections::HashMap<PublicKey, usize> = std::collections::HashMap::new();
+    let mut per_domain: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    ranked.retain(|(relay, _, pubkey)| {
+        let domain = apex_domain(relay.url.as_url_crate_url().host_str().unwrap_or(""));
+        if config.max_per_operator != 0
+            && per_operator.get(pubkey).copied().unwrap_or(0) >= config.max_per_operator
+        {
+            return false;
+        }
+        if config.max_per_domain != 0
+            && per_domain.get(&domain).copied().unwrap_or(0) >= config.max_per_domain
+        {
+            return false;
+        }
+        *per_operator.entry(*pubkey).or_insert(0) += 1;
+        *per_domain.entry(domain).or_insert(0) += 1;
+        true
+    });
+
+    let top = ranked.iter().take(config.count);
+
+    match config.format {
+        OutputFormat::Text => {
+            for (relay, scoring, _pubkey) in top {
+                println!("{} {:?}", relay.url, scoring);
+            }
+        }
+        OutputFormat::Json => {
+            let entries: Vec<RankedEntry> = top
+                .map(|(relay, scoring, pubkey)| RankedEntry {
+                    url: relay.url.clone(),
+                    pubkey: *pubkey,
+                    scoring: scoring.clone(),
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+        OutputFormat::Nip65 => {
+            // An unsigned kind:10002 relay-list event. The caller fills in
+            // their own pubkey/created_at/id/sig and publishes it.
+            let tags: Vec<Vec<String>> = top
+                .map(|(relay, _, _)| vec!["r".to_owned(), relay.url.as_str().to_owned()])
+                .collect();
+            let event = serde_json::json!({
+                "kind": 10002,
+                "tags": tags,
+                "content": "",
+            });
+            println!("{}", serde_json::to_string_pretty(&event)?);
+        }
     }
 
     Ok(())
 }
 
-pub fn rank(relay: &Relay) -> Scoring {
+/// The apex ("base") domain of a host: the last two labels, e.g. both
+/// `relay.example.com` and `news.example.com` collapse to `example.com`. Hosts
+/// with fewer than two labels (or IP literals) are returned unchanged.
+pub fn apex_domain(host: &str) -> String {
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() >= 2 && host.parse::<std::net::Ipv4Addr>().is_err() {
+        labels[labels.len() - 2..].join(".")
+    } else {
+        host.to_owned()
+    }
+}
+
+/// A single ranked relay as emitted by `--format json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RankedEntry {
+    pub url: RelayUrl,
+    pub pubkey: PublicKey,
+    pub scoring: Scoring,
+}
+
+pub fn rank(relay: &Relay, z: f32) -> Scoring {
     let last_connected_at = match relay.last_connected_at {
         None => 0,
         Some(time) => time,
@@ -96,11 +360,42 @@ pub fn rank(relay: &Relay) -> Scoring {
     let attempts = relay.success_count + relay.failure_count;
     let success = relay.success_count;
     let rate = relay.success_count as f32 / attempts as f32;
-    let log_attempts = (attempts as f32).log2();
 
     let age_penalty_divisor = 1.0 + ago as f32 / 86400.0;
 
-    let score = rate.powf(1.414) * log_attempts * log_attempts / age_penalty_divisor;
+    // Responsiveness: a relay that connects but never produces an EOSE, or
+    // whose last EOSE trails its last successful connection by a long time, is
+    // dead for reading. Measure how far the EOSE lags the connection and decay
+    // the score by that gap (a day of lag roughly halves it). A relay that has
+    // never sent an EOSE gets a fixed divisor of 4, the same as three days of
+    // lag; a longer lag is penalized more heavily than that.
+    let now = Unixtime::now().unwrap().0;
+    let eose_ago = relay.last_general_eose_at.map(|t| now - t as i64);
+    let eose_penalty_divisor = match (relay.last_general_eose_at, relay.last_connected_at) {
+        (Some(eose), Some(connected)) => {
+            let lag = (connected as i64 - eose as i64).max(0);
+            1.0 + lag as f32 / 86400.0
+        }
+        (Some(_), None) => 1.0,
+        (None, _) => 4.0,
+    };
+
+    // A relay we kept despite requiring AUTH is usable but not freely open, so
+    // halve its score rather than letting it compete on equal footing.
+    let auth_penalty = match &relay.nip11 {
+        Some(nip11) => match &nip11.limitation {
+            Some(l) if l.auth_required == Some(true) => 0.5,
+            _ => 1.0,
+        },
+        None => 1.0,
+    };
+
+    // Wilson score lower confidence bound: rank by positive rate while
+    // automatically penalizing small samples, so a proven 9800/10000 relay
+    // outranks a lucky 2/2 one without the old log-attempts fudge factor.
+    let score = wilson_lower_bound(success, attempts, z) / age_penalty_divisor
+        / eose_penalty_divisor
+        * auth_penalty;
 
     Scoring {
         score,
@@ -108,5 +403,18 @@ pub fn rank(relay: &Relay) -> Scoring {
         attempts,
         success,
         rate,
+        eose_ago,
+    }
+}
+
+/// Lower bound of the Wilson score interval for `s` successes out of `n`
+/// attempts at the given z-value. Returns 0 when there are no attempts.
+pub fn wilson_lower_bound(s: u64, n: u64, z: f32) -> f32 {
+    if n == 0 {
+        return 0.0;
     }
+    let n = n as f32;
+    let phat = s as f32 / n;
+    (phat + z * z / (2.0 * n) - z * ((phat * (1.0 - phat) + z * z / (4.0 * n)) / n).sqrt())
+        / (1.0 + z * z / n)
 }